@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use super::error::ImageSimilarityError;
+use super::cache::HashCache;
+use super::image_similarity::{compute_phash_directory, hamming_bits, HashAlgo};
+
+/// One entry in the tree: an image's file path and packed hash, plus its children
+/// keyed by their integer hamming distance to this node
+struct Node {
+    path: String,
+    hash: Vec<u8>,
+    children: HashMap<u32, Node>,
+}
+
+impl Node {
+    fn new(path: String, hash: Vec<u8>) -> Self {
+        Node { path, hash, children: HashMap::new() }
+    }
+
+    // descend the edge labeled by the distance to this node, creating it if absent
+    fn insert(&mut self, path: String, hash: Vec<u8>) -> Result<(), ImageSimilarityError> {
+        let distance = hamming_bits(&self.hash, &hash)?;
+        match self.children.get_mut(&distance) {
+            Some(child) => child.insert(path, hash),
+            None => {
+                self.children.insert(distance, Node::new(path, hash));
+                Ok(())
+            }
+        }
+    }
+
+    // report this node if it's within `max_distance` of `target`, then recurse only
+    // into child edges the triangle inequality can't rule out
+    fn query(&self, target: &[u8], max_distance: u32, result: &mut Vec<(f64, String)>) -> Result<(), ImageSimilarityError> {
+        let distance = hamming_bits(&self.hash, target)?;
+        if distance <= max_distance {
+            let bit_count = (self.hash.len() * 8) as f64;
+            result.push((1.0 - distance as f64 / bit_count, self.path.clone()));
+        }
+
+        let low = distance.saturating_sub(max_distance);
+        let high = distance + max_distance;
+        for (_, child) in self.children.iter().filter(|(&edge, _)| edge >= low && edge <= high) {
+            child.query(target, max_distance, result)?;
+        }
+        Ok(())
+    }
+}
+
+/// A BK-tree over packed phashes, using hamming distance as the metric, so a
+/// near-duplicate query can prune most of the tree instead of scanning every
+/// entry like `similarity_directory`/`similarity_file_directory` do
+pub struct PhashIndex {
+    root: Option<Node>,
+}
+
+impl PhashIndex {
+    /// Find all indexed images within `max_distance` hamming bits of `hash`, sorted
+    /// by similarity desc
+    ///
+    /// # Example
+    /// ```rust
+    /// let (index, _warnings) = build_index("/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"], HashAlgo::Dct, None).unwrap();
+    /// println!("{:#?}", index.query(&some_hash, 8));
+    /// ```
+    pub fn query(&self, hash: &[u8], max_distance: u32) -> Result<Vec<(f64, String)>, ImageSimilarityError> {
+        let mut result = Vec::new();
+        if let Some(root) = &self.root {
+            root.query(hash, max_distance, &mut result)?;
+        }
+        result.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        Ok(result)
+    }
+}
+
+// build a tree over an already-scanned set of (path, hash) pairs, so callers that
+// already have the hashes around (e.g. `similarity_directory_indexed`) don't pay
+// for a second directory scan just to get a `PhashIndex`
+pub(crate) fn build_index_from(images: Vec<(String, Vec<u8>)>) -> Result<PhashIndex, ImageSimilarityError> {
+    let mut images = images.into_iter();
+    let root = match images.next() {
+        Some((path, hash)) => {
+            let mut root = Node::new(path, hash);
+            for (path, hash) in images {
+                root.insert(path, hash)?;
+            }
+            Some(root)
+        },
+        None => None,
+    };
+    Ok(PhashIndex { root })
+}
+
+/// Build a BK-tree index over all phashes of images with allowed extensions in
+/// given directory, plus one warning per matching file that couldn't be decoded
+///
+/// # Example
+/// ```rust
+/// let (index, warnings) = build_index("/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"], HashAlgo::Dct, None).unwrap();
+/// ```
+pub fn build_index(directory: &str, allowed_ext: &Vec<&str>, algo: HashAlgo, cache: Option<&mut HashCache>) -> Result<(PhashIndex, Vec<String>), ImageSimilarityError> {
+    let scan = compute_phash_directory(directory, allowed_ext, algo, cache);
+    Ok((build_index_from(scan.hashes)?, scan.warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> Vec<u8> {
+        vec![byte]
+    }
+
+    #[test]
+    fn query_finds_an_exact_match() {
+        let index = build_index_from(vec![("a.png".to_string(), hash(0b0000_0000))]).unwrap();
+        let result = index.query(&hash(0b0000_0000), 0).unwrap();
+        assert_eq!(result, vec![(1.0, "a.png".to_string())]);
+    }
+
+    #[test]
+    fn query_excludes_entries_outside_max_distance() {
+        // 0b0000_0000 and 0b0000_0111 differ by 3 bits
+        let index = build_index_from(vec![("a.png".to_string(), hash(0b0000_0000))]).unwrap();
+        assert_eq!(index.query(&hash(0b0000_0111), 2).unwrap(), Vec::new());
+        assert_eq!(index.query(&hash(0b0000_0111), 3).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn query_prunes_via_triangle_inequality_but_still_finds_matches_below_the_root() {
+        // build a small tree where the match is reachable only through a child
+        // edge the triangle inequality must not discard
+        let index = build_index_from(vec![
+            ("root.png".to_string(), hash(0b0000_0000)),
+            ("near.png".to_string(), hash(0b0000_0001)),
+            ("far.png".to_string(), hash(0b1111_1111)),
+        ]).unwrap();
+
+        let mut result = index.query(&hash(0b0000_0000), 1).unwrap();
+        result.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(result, vec![
+            (0.875, "near.png".to_string()),
+            (1.0, "root.png".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn query_on_an_empty_index_returns_no_results() {
+        let index = build_index_from(Vec::new()).unwrap();
+        assert_eq!(index.query(&hash(0), 8).unwrap(), Vec::new());
+    }
+}
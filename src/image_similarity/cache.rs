@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::time::UNIX_EPOCH;
+use super::error::ImageSimilarityError;
+
+/// A cached hash: the file's size and modification time when it was hashed,
+/// the key identifying which algorithm/settings produced it, plus the packed
+/// hash itself
+struct CacheEntry {
+    size: u64,
+    mtime: u64,
+    algo_key: String,
+    hash: Vec<u8>,
+}
+
+/// An on-disk cache of image hashes keyed by absolute path, invalidated
+/// whenever a file's size or modification time no longer match what was
+/// cached, so rescans of an unchanged directory skip `imread`/`compute_hash`
+/// entirely
+///
+/// Entries also carry an `algo_key` (the algorithm plus its resize/length
+/// settings) so switching `--algo` against an existing cache file doesn't
+/// hand back another algorithm's hash bytes: aHash/dHash/pHash all pack to
+/// the same byte length at the default settings, so there'd otherwise be no
+/// length mismatch to catch the error
+///
+/// # Example
+/// ```rust
+/// let mut cache = HashCache::load("/PATH/TO/CACHE");
+/// if let Some((size, mtime)) = file_metadata("/PATH/TO/IMAGE") {
+///     match cache.get("/PATH/TO/IMAGE", size, mtime, "phash:64:16") {
+///         Some(hash) => println!("{:?}", hash),
+///         None => println!("not cached"),
+///     }
+/// }
+/// cache.save("/PATH/TO/CACHE").unwrap();
+/// ```
+pub struct HashCache {
+    entries: HashMap<String, CacheEntry>,
+    dirty: bool,
+}
+
+impl HashCache {
+    /// Load a cache file, starting from an empty cache if it doesn't exist yet
+    /// or is unreadable
+    pub fn load(path: &str) -> HashCache {
+        let mut entries = HashMap::new();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+                if let Some((path, entry)) = parse_line(&line) {
+                    entries.insert(path, entry);
+                }
+            }
+        }
+        HashCache { entries, dirty: false }
+    }
+
+    /// Look up a cached hash for `path`, only if `size`/`mtime` still match
+    /// what was cached and it was hashed with the same `algo_key`
+    pub fn get(&self, path: &str, size: u64, mtime: u64, algo_key: &str) -> Option<&Vec<u8>> {
+        self.entries.get(path)
+            .filter(|entry| entry.size == size && entry.mtime == mtime && entry.algo_key == algo_key)
+            .map(|entry| &entry.hash)
+    }
+
+    /// Record a freshly computed hash for `path`
+    pub fn put(&mut self, path: String, size: u64, mtime: u64, algo_key: String, hash: Vec<u8>) {
+        self.entries.insert(path, CacheEntry { size, mtime, algo_key, hash });
+        self.dirty = true;
+    }
+
+    /// Persist the cache back to `path`, if anything changed since it was loaded
+    pub fn save(&self, path: &str) -> Result<(), ImageSimilarityError> {
+        if !self.dirty { return Ok(()); }
+
+        let mut file = File::create(path)
+            .map_err(|e| ImageSimilarityError { reason: format!("failed to write cache file \"{}\": {}", path, e) })?;
+        for (file_path, entry) in &self.entries {
+            writeln!(file, "{}\t{}\t{}\t{}\t{}", file_path, entry.size, entry.mtime, entry.algo_key, hex_encode(&entry.hash))
+                .map_err(|e| ImageSimilarityError { reason: format!("failed to write cache file \"{}\": {}", path, e) })?;
+        }
+        Ok(())
+    }
+}
+
+// one line is `path\tsize\tmtime\talgo_key\thex_hash`
+fn parse_line(line: &str) -> Option<(String, CacheEntry)> {
+    let mut parts = line.splitn(5, '\t');
+    let path = parts.next()?.to_string();
+    let size: u64 = parts.next()?.parse().ok()?;
+    let mtime: u64 = parts.next()?.parse().ok()?;
+    let algo_key = parts.next()?.to_string();
+    let hash = hex_decode(parts.next()?)?;
+    Some((path, CacheEntry { size, mtime, algo_key, hash }))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 { return None; }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+/// Read a file's size and modification time (as unix seconds), for cache keying
+pub fn file_metadata(path: &str) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some((metadata.len(), mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trips_through_encode_and_decode() {
+        let bytes = vec![0x00, 0x0f, 0xa5, 0xff];
+        assert_eq!(hex_decode(&hex_encode(&bytes)), Some(bytes));
+    }
+
+    #[test]
+    fn hex_decode_rejects_odd_length_input() {
+        assert_eq!(hex_decode("abc"), None);
+    }
+
+    #[test]
+    fn hex_decode_rejects_non_hex_input() {
+        assert_eq!(hex_decode("zz"), None);
+    }
+
+    #[test]
+    fn parse_line_round_trips_a_written_entry() {
+        let line = format!("/some/path.png\t1234\t5678\tphash:64:16\t{}", hex_encode(&[0xde, 0xad, 0xbe, 0xef]));
+        let (path, entry) = parse_line(&line).expect("line should parse");
+        assert_eq!(path, "/some/path.png");
+        assert_eq!(entry.size, 1234);
+        assert_eq!(entry.mtime, 5678);
+        assert_eq!(entry.algo_key, "phash:64:16");
+        assert_eq!(entry.hash, vec![0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn parse_line_rejects_a_truncated_line() {
+        assert!(parse_line("/some/path.png\t1234\t5678").is_none());
+    }
+
+    #[test]
+    fn get_misses_when_algo_key_differs() {
+        let mut cache = HashCache { entries: HashMap::new(), dirty: false };
+        cache.put("/some/path.png".to_string(), 1234, 5678, "phash:64:16".to_string(), vec![0xaa]);
+        assert_eq!(cache.get("/some/path.png", 1234, 5678, "phash:64:16"), Some(&vec![0xaa]));
+        assert_eq!(cache.get("/some/path.png", 1234, 5678, "ahash:64:16"), None);
+    }
+
+    #[test]
+    fn get_misses_when_size_or_mtime_changed() {
+        let mut cache = HashCache { entries: HashMap::new(), dirty: false };
+        cache.put("/some/path.png".to_string(), 1234, 5678, "phash:64:16".to_string(), vec![0xaa]);
+        assert_eq!(cache.get("/some/path.png", 1, 5678, "phash:64:16"), None);
+        assert_eq!(cache.get("/some/path.png", 1234, 1, "phash:64:16"), None);
+    }
+}
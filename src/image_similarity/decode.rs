@@ -0,0 +1,89 @@
+use opencv::core::{Mat, CV_8UC1};
+use opencv::imgcodecs::imread;
+use super::error::ImageSimilarityError;
+
+/// Extensions scanned when a caller doesn't pass `--ext` (an explicit `--ext`
+/// list replaces them): png/jpg/jpeg plus the common RAW formats out of
+/// cameras and HEIC/HEIF, the format iPhones save photos in. Actually decoding them still
+/// requires the matching cargo feature (`heif`/`raw`); without it they're
+/// still recognized, so a scan surfaces them as a skipped-file warning
+/// instead of pretending they were never there
+pub const DEFAULT_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg",
+    "heic", "heif",
+    "cr2", "nef", "arw", "dng", "orf", "rw2", "raf",
+];
+
+/// Load `path` as a grayscale `Mat`, falling back to a HEIF/RAW decoder for
+/// formats `imread` doesn't understand
+///
+/// # Example
+/// ```rust
+/// match imread_any("/PATH/TO/IMAGE.heic") {
+///    Ok(img) => println!("{}x{}", img.cols().unwrap(), img.rows().unwrap()),
+///    Err(e) => println!("{}", e),
+/// }
+/// ```
+pub fn imread_any(path: &str) -> Result<Mat, ImageSimilarityError> {
+    // imread itself never errors on an unsupported format, it just hands back
+    // an empty Mat, so an empty size is what actually means "try the fallback"
+    if let Ok(img) = imread(path, 0) {
+        if img.cols()? > 0 && img.rows()? > 0 {
+            return Ok(img);
+        }
+    }
+
+    match path.rsplit('.').next().unwrap_or("").to_lowercase().as_str() {
+        "heic" | "heif" => decode_heif(path),
+        "cr2" | "nef" | "arw" | "dng" | "orf" | "rw2" | "raf" => decode_raw(path),
+        _ => Err(ImageSimilarityError { reason: format!("\"{}\" could not be opened as an image", path) }),
+    }
+}
+
+// wrap a caller-owned grayscale buffer in a `Mat`, copying it so the `Mat`
+// doesn't outlive the buffer it was built from
+fn gray_mat_from_pixels(width: i32, height: i32, pixels: &[u8]) -> Result<Mat, ImageSimilarityError> {
+    let borrowed = unsafe {
+        Mat::new_rows_cols_with_data(height, width, CV_8UC1, pixels.as_ptr() as *mut _, opencv::core::Mat_AUTO_STEP)?
+    };
+    Ok(borrowed.clone()?)
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif(path: &str) -> Result<Mat, ImageSimilarityError> {
+    // HEIF images are stored as YCbCr; the luma plane alone is already a fine
+    // grayscale source, no need to decode and discard the chroma planes
+    let ctx = libheif_rs::HeifContext::read_from_file(path)
+        .map_err(|e| ImageSimilarityError { reason: format!("failed to open HEIF file \"{}\": {}", path, e) })?;
+    let handle = ctx.primary_image_handle()
+        .map_err(|e| ImageSimilarityError { reason: format!("failed to read HEIF image \"{}\": {}", path, e) })?;
+    let image = handle.decode(libheif_rs::ColorSpace::YCbCr(libheif_rs::Chroma::C420), false)
+        .map_err(|e| ImageSimilarityError { reason: format!("failed to decode HEIF file \"{}\": {}", path, e) })?;
+    let plane = image.planes().y
+        .ok_or_else(|| ImageSimilarityError { reason: format!("HEIF file \"{}\" has no luma plane", path) })?;
+    gray_mat_from_pixels(plane.width as i32, plane.height as i32, plane.data)
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(path: &str) -> Result<Mat, ImageSimilarityError> {
+    Err(ImageSimilarityError { reason: format!("\"{}\" is a HEIC/HEIF file; rebuild with the \"heif\" feature to read it", path) })
+}
+
+#[cfg(feature = "raw")]
+fn decode_raw(path: &str) -> Result<Mat, ImageSimilarityError> {
+    // camera RAW is bayer-pattern sensor data; demosaicing it into a full
+    // color image is unnecessary work for a grayscale perceptual hash, so the
+    // sensor values are used directly, scaled down to 8 bits
+    let image = rawloader::decode_file(path)
+        .map_err(|e| ImageSimilarityError { reason: format!("failed to decode RAW file \"{}\": {}", path, e) })?;
+    let pixels: Vec<u8> = match image.data {
+        rawloader::RawImageData::Integer(data) => data.iter().map(|&v| (v >> 8) as u8).collect(),
+        rawloader::RawImageData::Float(data) => data.iter().map(|&v| (v.min(1.0).max(0.0) * 255.0) as u8).collect(),
+    };
+    gray_mat_from_pixels(image.width as i32, image.height as i32, &pixels)
+}
+
+#[cfg(not(feature = "raw"))]
+fn decode_raw(path: &str) -> Result<Mat, ImageSimilarityError> {
+    Err(ImageSimilarityError { reason: format!("\"{}\" is a RAW file; rebuild with the \"raw\" feature to read it", path) })
+}
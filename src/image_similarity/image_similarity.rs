@@ -1,186 +1,454 @@
+pub mod bk_tree;
+pub mod cache;
+pub mod decode;
+pub mod threshold;
+
+use std::fs;
 use opencv::core::{Mat, Scalar, Size_, dct, CV_64FC1};
-use opencv::imgcodecs::imread;
 use opencv::imgproc::{self, cvt_color, resize, COLOR_RGB2GRAY, COLOR_RGBA2GRAY};
 use super::error::ImageSimilarityError;
+use self::cache::{file_metadata, HashCache};
+use self::decode::imread_any;
+use rayon::prelude::*;
 use walkdir::WalkDir;
 
+/// Which perceptual hashing algorithm to hash images with
+///
+/// `Average` and `Difference` downscale straight to the hash dimensions, so
+/// they're cheap; `Dct` resizes larger first and keeps only the low-frequency
+/// coefficients, which costs more but is the most robust to re-encoding and
+/// scaling. All three produce the same packed-bit representation, so the
+/// same `hamming_distance`/BK-tree comparisons work unchanged regardless of
+/// which one was used to build a given hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgo {
+    /// aHash: bit set when a pixel is at or above the mean of the downscaled image
+    Average,
+    /// dHash: bit set when a pixel is darker than its right neighbor; robust to brightness/gamma shifts
+    Difference,
+    /// pHash: bit set when a low-frequency DCT coefficient is at or above their mean
+    Dct,
+}
+
+impl HashAlgo {
+    /// Parse a `--algo` flag value
+    ///
+    /// Named `parse` rather than `from_str` so it doesn't collide with
+    /// `std::str::FromStr`'s method name (an inherent `from_str` trips
+    /// `clippy::should_implement_trait`)
+    pub fn parse(name: &str) -> Result<HashAlgo, ImageSimilarityError> {
+        match name {
+            "ahash" | "average" => Ok(HashAlgo::Average),
+            "dhash" | "difference" => Ok(HashAlgo::Difference),
+            "phash" | "dct" => Ok(HashAlgo::Dct),
+            _ => Err(ImageSimilarityError { reason: format!("unknown hash algorithm \"{}\", expected one of ahash, dhash, phash", name) }),
+        }
+    }
+
+    // canonical short name, used as part of the cache key so switching
+    // `--algo` against an existing cache doesn't return another algorithm's hash
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Average => "ahash",
+            HashAlgo::Difference => "dhash",
+            HashAlgo::Dct => "phash",
+        }
+    }
+}
+
+// identifies which algorithm and resize settings a cached hash was computed
+// with; aHash/dHash/pHash all pack to the same byte length at the default
+// `64, 16` settings, so the byte length alone can't catch a stale cache entry
+fn cache_key(algo: HashAlgo, length: i32, dct_length: i32) -> String {
+    format!("{}:{}:{}", algo.name(), length, dct_length)
+}
+
 /// Compute the similarity of two given image
 ///
 /// # Example
 /// ```rust
 /// let image_a = opencv::imgcodecs::imread("/PATH/TO/IMAGE/A", 0).expect("Invaild image file a");
 /// let image_b = opencv::imgcodecs::imread("/PATH/TO/IMAGE/B", 0).expect("Invaild image file b");
-/// match similarity(&image_a, &image_b, 64, 16) {
+/// match similarity(&image_a, &image_b, HashAlgo::Dct, 64, 16) {
 ///    Ok(similarity) => println!("{}", similarity),
 ///    Err(e) => println!("{}", e),
 /// }
 /// ```
-pub fn similarity(img_a: &Mat, img_b: &Mat, length: i32, dct_length: i32) -> Result<f64, ImageSimilarityError> {
+pub fn similarity(img_a: &Mat, img_b: &Mat, algo: HashAlgo, length: i32, dct_length: i32) -> Result<f64, ImageSimilarityError> {
     // of course length and dct_length should be greater than 0
     if length <= 0 { return Err(ImageSimilarityError { reason: format!("length should be a positive number instead of {}", length)}) }
     if dct_length <= 0 { return Err(ImageSimilarityError { reason: format!("dct_length should be a positive number instead of {}", length)}) }
-    
-    // try to compute phash for `img_a` and `img_b`
-    let phash_img_a = compute_phash(img_a, length, dct_length)?;
-    let phash_img_b = compute_phash(img_b, length, dct_length)?;
+
+    // try to compute the hash for `img_a` and `img_b`
+    let hash_img_a = compute_hash(img_a, algo, length, dct_length)?;
+    let hash_img_b = compute_hash(img_b, algo, length, dct_length)?;
     // compute their hamming distance
-    Ok(hamming_distance(&phash_img_a, &phash_img_b))
+    hamming_distance(&hash_img_a, &hash_img_b)
 }
 
 /// Compute similarities of all images with allowed extensions in given directory
 ///
+/// Alongside the similarity pairs, returns one warning per file that matched
+/// `allowed_ext` but couldn't be decoded (a HEIC/RAW file without the matching
+/// cargo feature, a corrupt file, etc), so callers scanning photo libraries
+/// learn which files were skipped and why instead of those files silently
+/// vanishing from the result
+///
 /// # Example
 /// ```rust
-/// match similarity_directory("/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"]) {
-///    Some(result) => println!("{:#?}", result),
-///    None => println!("No available images with given extensions in the given directory"),
+/// match similarity_directory("/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"], HashAlgo::Dct, None, None) {
+///    (Some(result), warnings) => println!("{:#?} ({} warnings)", result, warnings.len()),
+///    (None, _) => println!("No available images with given extensions in the given directory"),
 /// };
 /// ```
-pub fn similarity_directory(directory: &str, allowed_ext: &Vec<&str>) -> Option<Vec<(f64, String, String)>> {
+pub fn similarity_directory(directory: &str, allowed_ext: &Vec<&str>, algo: HashAlgo, cache: Option<&mut HashCache>, max_distance: Option<u32>) -> (Option<Vec<(f64, String, String)>>, Vec<String>) {
     // compute all phashes in directory with given allowed file extensions
-    let all_image_file = compute_phash_directory(directory, allowed_ext);
+    let scan = compute_phash_directory(directory, allowed_ext, algo, cache);
+    let all_image_file = scan.hashes;
     // the result should be an array of tuple (similarity, image a, image b)
     let mut result: Vec<(f64, String, String)> = Vec::new();
-    match all_image_file.len() {
+    let similarities = match all_image_file.len() {
         // 0 is boring
         0 => None,
-        // so is 1 
+        // so is 1
         1 => {
             result.push((1.0, all_image_file[0].0.clone(), all_image_file[0].0.clone()));
             Some(result)
         },
         _ => {
-            // compute hamming distance for all image pairs
-            for a_index in 0..(all_image_file.len() - 1) {
-                for b_index in (a_index + 1)..all_image_file.len() {
-                    let img_a_data = &all_image_file[a_index];
-                    let img_b_data = &all_image_file[b_index];
-                    result.push((hamming_distance(&img_a_data.1, &img_b_data.1), img_a_data.0.clone(), img_b_data.0.clone()));
+            // enumerate all pairs up front so the hamming distances can be computed
+            // across the rayon pool instead of on a single thread
+            let pairs: Vec<(usize, usize)> = (0..(all_image_file.len() - 1))
+                .flat_map(|a_index| ((a_index + 1)..all_image_file.len()).map(move |b_index| (a_index, b_index)))
+                .collect();
+            let mut result: Vec<(f64, String, String)> = pairs.into_par_iter().filter_map(|(a_index, b_index)| {
+                let img_a_data = &all_image_file[a_index];
+                let img_b_data = &all_image_file[b_index];
+                let (similarity, distance) = similarity_and_distance(&img_a_data.1, &img_b_data.1);
+                // dropping pairs below the threshold here avoids materializing the
+                // full O(n^2) result vector when the caller only wants near-duplicates
+                if let Some(max_distance) = max_distance {
+                    if distance > max_distance { return None; }
                 }
-            }
+                Some((similarity, img_a_data.0.clone(), img_b_data.0.clone()))
+            }).collect();
             // sort by similarity desc
             result.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
             Some(result)
         }
-    }
+    };
+    (similarities, scan.warnings)
 }
 
 /// Compute similarities of given image with all images that ends in allowed extensions in given directory
 ///
+/// Alongside the similarity results, returns one warning per file that matched
+/// `allowed_ext` but couldn't be decoded, same as `similarity_directory`
+///
 /// # Example
 /// ```rust
 /// let image = opencv::imgcodecs::imread("/PATH/TO/IMAGE", 0).expect("Invaild image file");
-/// match similarity_file_directory(&image, "/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"]) {
-///    Some(result) => println!("{:#?}", result),
-///    None => println!("No available images with given extensions in the given directory"),
+/// match similarity_file_directory(&image, "/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"], HashAlgo::Dct, None, None) {
+///    Ok((Some(result), warnings)) => println!("{:#?} ({} warnings)", result, warnings.len()),
+///    Ok((None, _)) => println!("No available images with given extensions in the given directory"),
+///    Err(e) => println!("{}", e),
 /// };
 /// ```
-pub fn similarity_file_directory(image: &Mat, directory: &str, allowed_ext: &Vec<&str>) -> Result<Option<Vec<(f64, String)>>, ImageSimilarityError> {
-    let image_phash = compute_phash(&image, 64, 16)?;
+pub fn similarity_file_directory(image: &Mat, directory: &str, allowed_ext: &Vec<&str>, algo: HashAlgo, cache: Option<&mut HashCache>, max_distance: Option<u32>) -> Result<(Option<Vec<(f64, String)>>, Vec<String>), ImageSimilarityError> {
+    let image_phash = compute_hash(&image, algo, 64, 16)?;
     // compute all phashes in directory with given allowed file extensions
-    let all_image_file = compute_phash_directory(directory, allowed_ext);
-    
-    match all_image_file.len() {
+    let scan = compute_phash_directory(directory, allowed_ext, algo, cache);
+    let all_image_file = scan.hashes;
+
+    let similarities = match all_image_file.len() {
         // 0 is boring
-        0 => Ok(None),
+        0 => None,
         _ => {
             // compute hamming distance for all image pairs
             // the result should be an array of tuple (similarity, image in directory)
-            let mut result: Vec<(f64, String)> = all_image_file.iter().map(|image_data| {
-                (hamming_distance(&image_phash, &image_data.1), image_data.0.clone())
+            let mut result: Vec<(f64, String)> = all_image_file.iter().filter_map(|image_data| {
+                let (similarity, distance) = similarity_and_distance(&image_phash, &image_data.1);
+                // dropping entries below the threshold here avoids materializing the
+                // full linear-scan result vector when the caller only wants near-duplicates
+                if let Some(max_distance) = max_distance {
+                    if distance > max_distance { return None; }
+                }
+                Some((similarity, image_data.0.clone()))
             }).collect();
             // sort by similarity desc
             result.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
-            Ok(Some(result))
+            Some(result)
         }
+    };
+    Ok((similarities, scan.warnings))
+}
+
+/// Like `similarity_directory`, but queries a BK-tree index instead of enumerating
+/// every pair, so a `--max-distance`/`--level` scan of a large directory only
+/// touches the pairs the triangle inequality can't rule out
+///
+/// Agrees with `similarity_directory` on the single-image case: a directory
+/// with exactly one matching image reports it as a self-pair `(1.0, p, p)`,
+/// rather than an empty result just because the `other_path > path` dedup
+/// below would otherwise filter out an index's only (self) match
+///
+/// # Example
+/// ```rust
+/// match similarity_directory_indexed("/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"], HashAlgo::Dct, None, 8) {
+///    Ok((result, warnings)) => println!("{:#?} ({} warnings)", result, warnings.len()),
+///    Err(e) => println!("{}", e),
+/// };
+/// ```
+pub fn similarity_directory_indexed(directory: &str, allowed_ext: &Vec<&str>, algo: HashAlgo, cache: Option<&mut HashCache>, max_distance: u32) -> Result<(Vec<(f64, String, String)>, Vec<String>), ImageSimilarityError> {
+    let scan = compute_phash_directory(directory, allowed_ext, algo, cache);
+
+    // 1 is boring, same as `similarity_directory`: there's no second image to
+    // pair against, so report the lone image as matching itself
+    if scan.hashes.len() == 1 {
+        let path = scan.hashes[0].0.clone();
+        return Ok((vec![(1.0, path.clone(), path)], scan.warnings));
     }
+
+    let index = bk_tree::build_index_from(scan.hashes.clone())?;
+
+    // query every hash against the tree in parallel; pairs are reported once,
+    // keeping only the ordering where the match's path sorts after the query's
+    let mut result: Vec<(f64, String, String)> = scan.hashes.par_iter()
+        .map(|(path, hash)| -> Result<Vec<(f64, String, String)>, ImageSimilarityError> {
+            let matches = index.query(hash, max_distance)?.into_iter()
+                .filter(|(_, other_path)| other_path > path)
+                .map(|(similarity, other_path)| (similarity, path.clone(), other_path))
+                .collect();
+            Ok(matches)
+        })
+        .collect::<Result<Vec<Vec<(f64, String, String)>>, ImageSimilarityError>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    // sort by similarity desc
+    result.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+    Ok((result, scan.warnings))
+}
+
+/// Like `similarity_file_directory`, but queries a BK-tree index instead of a
+/// linear scan, so near-duplicates of `image` can be found without touching
+/// most of the indexed directory
+///
+/// # Example
+/// ```rust
+/// let image = opencv::imgcodecs::imread("/PATH/TO/IMAGE", 0).expect("Invaild image file");
+/// match similarity_file_directory_indexed(&image, "/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"], HashAlgo::Dct, None, 8) {
+///    Ok((result, warnings)) => println!("{:#?} ({} warnings)", result, warnings.len()),
+///    Err(e) => println!("{}", e),
+/// };
+/// ```
+pub fn similarity_file_directory_indexed(image: &Mat, directory: &str, allowed_ext: &Vec<&str>, algo: HashAlgo, cache: Option<&mut HashCache>, max_distance: u32) -> Result<(Vec<(f64, String)>, Vec<String>), ImageSimilarityError> {
+    let image_phash = compute_hash(image, algo, 64, 16)?;
+    let (index, warnings) = bk_tree::build_index(directory, allowed_ext, algo, cache)?;
+    Ok((index.query(&image_phash, max_distance)?, warnings))
+}
+
+/// The result of scanning a directory for phashes: the hashes that were
+/// computed, plus one warning per matching file that couldn't be decoded
+pub(crate) struct PhashScanResult {
+    pub hashes: Vec<(String, Vec<u8>)>,
+    pub warnings: Vec<String>,
 }
 
 /// Compute all phashes in directory with given allowed file extensions
 ///
+/// Paths are canonicalized before hashing/caching, so the cache key (and the
+/// path returned in `scan.hashes`) is the same whether `directory` is given
+/// as an absolute path, a relative path, or reached through a symlink
+///
 /// # Example
 /// ```rust
-/// println!("{:#?}", compute_phash_directory("/PATH/TO/A/DIRECTORY"));
+/// let scan = compute_phash_directory("/PATH/TO/A/DIRECTORY", &vec!["png", "jpg", "jpeg"], HashAlgo::Dct, None);
+/// println!("{:#?}", scan.hashes);
+/// println!("{:#?}", scan.warnings);
 /// ```
-fn compute_phash_directory(directory: &str, allowed_ext: &Vec<&str>) -> Vec<(String, String)> {
-    // walk given directory
+pub(crate) fn compute_phash_directory(directory: &str, allowed_ext: &Vec<&str>, algo: HashAlgo, mut cache: Option<&mut HashCache>) -> PhashScanResult {
+    // walk the directory once, sequentially, splitting off the files the cache can
+    // already answer from the files that still need to be decoded and hashed
+    let key = cache_key(algo, 64, 16);
+    let mut result: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut to_hash: Vec<(String, Option<(u64, u64)>)> = Vec::new();
+
     WalkDir::new(directory).into_iter()
         .filter_map(|e| e.ok()) // keep all ok files
         .filter_map(|file_entry| {
             // filter by user given allowed file extensions
-            
-            // store path to the file
-            let filepath = file_entry.path().to_str().unwrap();
+
+            // store the absolute path to the file, so the same image reached
+            // through two different (e.g. relative vs. symlinked) directory
+            // roots hits the same cache entry instead of two separate ones
+            let filepath = fs::canonicalize(file_entry.path())
+                .ok()
+                .and_then(|p| p.to_str().map(String::from))
+                .unwrap_or_else(|| file_entry.path().to_str().unwrap().to_string());
             // split file path by `.`
             let parts: Vec<&str> = filepath.split('.').collect();
             // check whether the extension is allowed
             if let Some(_) = allowed_ext.iter().find(|&&ext| ext == parts[parts.len() - 1]) {
                 // keep
-                Some(String::from(filepath))
+                Some(filepath)
             } else {
                 // no
                 None
             }
-        }).filter_map(|file| {
-            // with all files with allowed extensions
-            
-            // try to load the file as image
-            let img = match imread(&file, 0) {
-                // proceed next step if successfully opened
-                Ok(img) => img,
-                // otherwise throw this file
-                Err(_) => return None,
-            };
-            // compute phash of this file with resize length 64 and dct length 16
-            match compute_phash(&img, 64, 16) {
-                // if nothing goes wrong while computing phash
-                // then return a tuple, (filepath, phash)
-                Ok(phash) => Some((file, phash)),
-                // otherwise throw this file
-                Err(_) => None
+        }).for_each(|file| {
+            let metadata = file_metadata(&file);
+            let cached_hash = cache.as_deref()
+                .zip(metadata)
+                .and_then(|(cache, (size, mtime))| cache.get(&file, size, mtime, &key).cloned());
+            match cached_hash {
+                Some(hash) => result.push((file, hash)),
+                None => to_hash.push((file, metadata)),
+            }
+        });
+
+    // decode + hash the remaining files across the rayon pool; for CPU-bound OpenCV
+    // decoding over thousands of files this is close to a linear speedup. decode
+    // failures (an unreadable HEIC/RAW file, a corrupt image, ...) are kept as
+    // warnings instead of silently dropping the file from the scan
+    let hashed: Vec<Result<(String, Option<(u64, u64)>, Vec<u8>), (String, ImageSimilarityError)>> = to_hash.into_par_iter()
+        .map(|(file, metadata)| {
+            // imread_any falls back to a HEIF/RAW decoder for formats imread can't open
+            let hash = imread_any(&file).and_then(|img| compute_hash(&img, algo, 64, 16));
+            match hash {
+                Ok(hash) => Ok((file, metadata, hash)),
+                Err(e) => Err((file, e)),
             }
-        }).collect()
+        }).collect();
+
+    let mut warnings = Vec::new();
+    let mut freshly_hashed: Vec<(String, Option<(u64, u64)>, Vec<u8>)> = Vec::new();
+    for outcome in hashed {
+        match outcome {
+            Ok(entry) => freshly_hashed.push(entry),
+            Err((file, e)) => warnings.push(format!("skipped \"{}\": {}", file, e)),
+        }
+    }
+
+    // writing back to the cache happens on this thread, once hashing is done
+    for (file, metadata, hash) in &freshly_hashed {
+        if let (Some(cache), Some((size, mtime))) = (cache.as_deref_mut(), metadata) {
+            cache.put(file.clone(), *size, *mtime, key.clone(), hash.clone());
+        }
+    }
+    result.extend(freshly_hashed.into_iter().map(|(file, _, hash)| (file, hash)));
+
+    PhashScanResult { hashes: result, warnings }
+}
+
+/// We need the image to be grayscale and resized to a reasonable size before
+/// any of the hashing algorithms can run on it
+fn assert_gray_and_size(img: &Mat, width: i32, height: i32) -> Result<Mat, ImageSimilarityError> {
+    // create a new Mat for the gray image
+    let mut gray = Mat::default()?;
+    // check number of channels of orginal image
+    match img.channels()? {
+        // it's already a grayscale image
+        // just copy it
+        1 => gray = img.clone()?,
+        // for image with 3 or 4 channels,
+        // convert it to grayscale
+        3 => cvt_color(&img, &mut gray, COLOR_RGB2GRAY, 0)?,
+        4 => cvt_color(&img, &mut gray, COLOR_RGBA2GRAY, 0)?,
+        // we don't support image with any other number of channels
+        _ => return Err(ImageSimilarityError { reason: format!("Image with {} channels is not supported yet", img.channels().unwrap()) }),
+    };
+
+    // create a new Mat for the resized image
+    let mut resized = Mat::default()?;
+    // specific size
+    let size = Size_::new(width, height);
+    // and resize the original image
+    resize(&gray, &mut resized, size, 0.0, 0.0, imgproc::INTER_LINEAR)?;
+    Ok(resized)
 }
 
-/// Compute pHash of given image
+/// Compute the bit-packed hash of given image using the chosen algorithm
+///
+/// `length` is the DCT resize side, only used by `HashAlgo::Dct`; `dct_length`
+/// is the hash's side length for every algorithm (the number of DCT
+/// coefficients kept per axis, or the aHash/dHash grid side)
 ///
 /// # Example
 /// ```rust
 /// let image = opencv::imgcodecs::imread("/PATH/TO/IMAGE", 0).expect("Invaild image file");
-/// match compute_phash(&image, 64, 16) {
-///    Ok(phash) => println!("{}", phash),
+/// match compute_hash(&image, HashAlgo::Dct, 64, 16) {
+///    Ok(hash) => println!("{:?}", hash),
 ///    Err(e) => println!("{}", e),
 /// }
 /// ```
-fn compute_phash(img: &Mat, length: i32, dct_length: i32) -> Result<String, ImageSimilarityError> {
-    // we need the image to be grayscale and resized to a reasonable size
-    fn assert_gray_and_size(img: &Mat, length: i32) -> Result<Mat, ImageSimilarityError> {
-        // create a new Mat for the gray image
-        let mut gray = Mat::default()?;
-        // check number of channels of orginal image
-        match img.channels()? {
-            // it's already a grayscale image
-            // just copy it
-            1 => gray = img.clone()?,
-            // for image with 3 or 4 channels,
-            // convert it to grayscale
-            3 => cvt_color(&img, &mut gray, COLOR_RGB2GRAY, 0)?,
-            4 => cvt_color(&img, &mut gray, COLOR_RGBA2GRAY, 0)?,
-            // we don't support image with any other number of channels
-            _ => return Err(ImageSimilarityError { reason: format!("Image with {} channels is not supported yet", img.channels().unwrap()) }),
-        };
-        
-        // create a new Mat for the resized image
-        let mut resized = Mat::default()?;
-        // specific size
-        let size = Size_::new(length, length);
-        // and resize the original image
-        resize(&gray, &mut resized, size, 0.0, 0.0, imgproc::INTER_LINEAR)?;
-        Ok(resized)
+fn compute_hash(img: &Mat, algo: HashAlgo, length: i32, dct_length: i32) -> Result<Vec<u8>, ImageSimilarityError> {
+    match algo {
+        HashAlgo::Dct => compute_phash(img, length, dct_length),
+        HashAlgo::Average => compute_ahash(img, dct_length),
+        HashAlgo::Difference => compute_dhash(img, dct_length),
     }
-    
+}
+
+/// Compute the aHash of given image: downscale to `length x length` grayscale,
+/// then set a bit per pixel that's at or above the mean pixel value
+fn compute_ahash(img: &Mat, length: i32) -> Result<Vec<u8>, ImageSimilarityError> {
+    let resized_gray = assert_gray_and_size(img, length, length)?;
+
+    let mut mean: f64 = 0.0;
+    for row in 0..length {
+        for col in 0..length {
+            let value: &u8 = resized_gray.at_2d(row, col)?;
+            mean += *value as f64;
+        }
+    }
+    mean /= (length * length) as f64;
+
+    let bit_count = (length * length) as usize;
+    let mut hash = vec![0u8; (bit_count + 7) / 8];
+    let mut bit_index = 0usize;
+    for row in 0..length {
+        for col in 0..length {
+            let value: &u8 = resized_gray.at_2d(row, col)?;
+            if *value as f64 >= mean {
+                hash[bit_index / 8] |= 1 << (bit_index % 8);
+            }
+            bit_index += 1;
+        }
+    }
+
+    Ok(hash)
+}
+
+/// Compute the dHash of given image: downscale to `(length + 1) x length`
+/// grayscale, then set a bit per pixel that's darker than its right neighbor.
+/// More robust to brightness/gamma shifts than aHash since it compares
+/// neighboring pixels instead of thresholding against a global mean.
+fn compute_dhash(img: &Mat, length: i32) -> Result<Vec<u8>, ImageSimilarityError> {
+    let resized_gray = assert_gray_and_size(img, length + 1, length)?;
+
+    let bit_count = (length * length) as usize;
+    let mut hash = vec![0u8; (bit_count + 7) / 8];
+    let mut bit_index = 0usize;
+    for row in 0..length {
+        for col in 0..length {
+            let left: &u8 = resized_gray.at_2d(row, col)?;
+            let right: &u8 = resized_gray.at_2d(row, col + 1)?;
+            if left < right {
+                hash[bit_index / 8] |= 1 << (bit_index % 8);
+            }
+            bit_index += 1;
+        }
+    }
+
+    Ok(hash)
+}
+
+/// Compute the pHash of given image
+fn compute_phash(img: &Mat, length: i32, dct_length: i32) -> Result<Vec<u8>, ImageSimilarityError> {
     // try to get the resized and grayscale image
-    let resized_gray = assert_gray_and_size(&img, length)?;
+    let resized_gray = assert_gray_and_size(&img, length, length)?;
 
     // convert the underlaying type of resized_gray into double
     let mut double_type_img = Mat::new_rows_cols_with_default(resized_gray.rows()?, resized_gray.cols()?, CV_64FC1, Scalar::new(0.0, 0.0, 0.0, 0.0))?;
@@ -201,46 +469,107 @@ fn compute_phash(img: &Mat, length: i32, dct_length: i32) -> Result<String, Imag
     mean -= dct_img.at(0)?;
     mean /= (length * length - 1) as f64;
     
-    // build the phash string of the given image
-    let mut phash = String::new();
+    // build the bit-packed phash of the given image: one bit per DCT coefficient,
+    // set when the coefficient is at or above the mean
+    let bit_count = (dct_length * dct_length) as usize;
+    let mut phash = vec![0u8; (bit_count + 7) / 8];
+    let mut bit_index = 0usize;
     for row in 0..dct_length {
         for col in 0..dct_length {
             let value: &f64 = dct_img.at(row + col * length)?;
-            if value < &mean { 
-                phash.push_str("0");
-            } else {
-                phash.push_str("1");
+            if value >= &mean {
+                phash[bit_index / 8] |= 1 << (bit_index % 8);
             }
+            bit_index += 1;
         }
     }
 
     Ok(phash)
 }
 
-/// Compute hamming distance of two given string
+/// Compute the similarity of two packed phashes: 1 minus their hamming
+/// distance normalized by the hash's bit count
+///
+/// Both hashes must have the same byte length (they were computed with the
+/// same `dct_length`); mismatched lengths are reported as an error instead of
+/// underflowing.
 ///
 /// # Example
 /// ```rust
-/// println!("{}", hamming_distance(&String::from("111"), &String::from("101")));
+/// println!("{}", hamming_distance(&vec![0b111], &vec![0b101]).unwrap());
 /// ```
-fn hamming_distance(a: &String, b: &String) -> f64 {
-    // get length of two strings
-    let len1 = a.len();
-    let len2 = b.len();
-    
-    // we only compute the hamming distance if the lengths are equal, but expect 0
-    match (len1, len2, len1 - len2) {
-        (_, _, 0) => {
-            let mut dist: f64 = 0.0;
-            for i in 0..len1 {
-                if a.chars().nth(i) != b.chars().nth(i) {
-                    dist += 1.0;
-                }
-            }
-            1.0 - dist / (len1 as f64)
-        },
-        (0, _, _) => 0.0,
-        (_, 0, _) => 0.0,
-        (_, _, _) => 0.0,
+fn hamming_distance(a: &[u8], b: &[u8]) -> Result<f64, ImageSimilarityError> {
+    let dist = hamming_bits(a, b)?;
+    let bit_count = (a.len() * 8) as f64;
+    Ok(1.0 - dist as f64 / bit_count)
+}
+
+/// Compute the raw hamming distance, in bits, between two packed phashes of equal length
+///
+/// # Example
+/// ```rust
+/// println!("{}", hamming_bits(&vec![0b111], &vec![0b101]).unwrap());
+/// ```
+pub(crate) fn hamming_bits(a: &[u8], b: &[u8]) -> Result<u32, ImageSimilarityError> {
+    if a.len() != b.len() {
+        return Err(ImageSimilarityError { reason: format!("cannot compare hashes of different length: {} bytes vs {} bytes", a.len(), b.len()) });
+    }
+    // popcount of the xor'd bytes gives the hamming distance in bits, allocation-free
+    Ok(a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum())
+}
+
+// both the normalized similarity and the raw bit distance are needed together to
+// filter by `--max-distance`/`--level` without computing the hamming distance twice;
+// hashes of differing length can't be compared meaningfully, so they're treated as unrelated
+fn similarity_and_distance(a: &[u8], b: &[u8]) -> (f64, u32) {
+    match hamming_bits(a, b) {
+        Ok(distance) => (1.0 - distance as f64 / (a.len() * 8) as f64, distance),
+        Err(_) => (0.0, u32::max_value()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hamming_bits_counts_differing_bits_across_bytes() {
+        assert_eq!(hamming_bits(&[0b1111_0000, 0b0000_0000], &[0b0000_0000, 0b1111_0000]).unwrap(), 8);
+    }
+
+    #[test]
+    fn hamming_bits_is_zero_for_identical_hashes() {
+        assert_eq!(hamming_bits(&[0xab, 0xcd], &[0xab, 0xcd]).unwrap(), 0);
+    }
+
+    #[test]
+    fn hamming_bits_rejects_mismatched_lengths() {
+        assert!(hamming_bits(&[0x00], &[0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn hamming_distance_normalizes_by_bit_count() {
+        // one bit differs out of 8
+        assert_eq!(hamming_distance(&[0b0000_0001], &[0b0000_0000]).unwrap(), 0.875);
+    }
+
+    #[test]
+    fn similarity_and_distance_matches_hamming_distance_when_lengths_agree() {
+        let (similarity, distance) = similarity_and_distance(&[0b0000_0001], &[0b0000_0000]);
+        assert_eq!(distance, 1);
+        assert_eq!(similarity, 0.875);
+    }
+
+    #[test]
+    fn similarity_and_distance_treats_mismatched_lengths_as_unrelated() {
+        let (similarity, distance) = similarity_and_distance(&[0x00], &[0x00, 0x00]);
+        assert_eq!(similarity, 0.0);
+        assert_eq!(distance, u32::max_value());
+    }
+
+    #[test]
+    fn cache_key_differs_by_algorithm() {
+        assert_ne!(cache_key(HashAlgo::Dct, 64, 16), cache_key(HashAlgo::Average, 64, 16));
+        assert_eq!(cache_key(HashAlgo::Dct, 64, 16), cache_key(HashAlgo::Dct, 64, 16));
     }
 }
@@ -0,0 +1,106 @@
+use super::error::ImageSimilarityError;
+
+// distance budgets for 8/16/32/64-bit hashes; for any other bit size the budget
+// of the largest tabulated size at or below it is scaled proportionally, and a
+// hash smaller than 8 bits uses the 8-bit budget as-is
+const HASH_SIZES: [u32; 4] = [8, 16, 32, 64];
+
+/// Named closeness levels for filtering `directory`/`match` results, mapped to
+/// concrete hamming-bit thresholds that scale with hash size so "high" stays a
+/// tighter bit budget than "veryhigh" regardless of which hash algorithm or
+/// resolution produced the hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToleranceLevel {
+    VerySmall,
+    Small,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+impl ToleranceLevel {
+    /// Parse a `--level` flag value
+    ///
+    /// Named `parse` rather than `from_str` so it doesn't collide with
+    /// `std::str::FromStr`'s method name (an inherent `from_str` trips
+    /// `clippy::should_implement_trait`)
+    pub fn parse(name: &str) -> Result<ToleranceLevel, ImageSimilarityError> {
+        match name {
+            "verysmall" => Ok(ToleranceLevel::VerySmall),
+            "small" => Ok(ToleranceLevel::Small),
+            "medium" => Ok(ToleranceLevel::Medium),
+            "high" => Ok(ToleranceLevel::High),
+            "veryhigh" => Ok(ToleranceLevel::VeryHigh),
+            _ => Err(ImageSimilarityError { reason: format!("unknown tolerance level \"{}\", expected one of verysmall, small, medium, high, veryhigh", name) }),
+        }
+    }
+
+    // distance budget at each of the tabulated 8/16/32/64-bit hash sizes
+    fn table(self) -> [u32; 4] {
+        match self {
+            ToleranceLevel::VerySmall => [0, 1, 2, 4],
+            ToleranceLevel::Small => [1, 2, 4, 8],
+            ToleranceLevel::Medium => [1, 3, 6, 12],
+            ToleranceLevel::High => [2, 4, 8, 16],
+            ToleranceLevel::VeryHigh => [3, 6, 12, 24],
+        }
+    }
+
+    /// The raw hamming-bit threshold this level maps to for a hash of `bit_size` bits
+    pub fn max_distance(self, bit_size: u32) -> u32 {
+        let table = self.table();
+        // pick the largest tabulated size that's still <= bit_size, then scale its
+        // budget proportionally to the actual hash size; below the smallest
+        // tabulated size there's nothing to scale from, so its budget is used as-is
+        match HASH_SIZES.iter().zip(table.iter())
+            .rev()
+            .find(|(&size, _)| size <= bit_size) {
+            Some((size, budget)) => (*budget as u64 * bit_size as u64 / *size as u64) as u32,
+            None => table[0],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_distance_matches_the_table_at_tabulated_sizes() {
+        assert_eq!(ToleranceLevel::Small.max_distance(8), 1);
+        assert_eq!(ToleranceLevel::Small.max_distance(16), 2);
+        assert_eq!(ToleranceLevel::Small.max_distance(32), 4);
+        assert_eq!(ToleranceLevel::Small.max_distance(64), 8);
+    }
+
+    #[test]
+    fn max_distance_scales_proportionally_for_a_larger_hash() {
+        // 256 bits is 4x the largest (64-bit) table entry, so its budget of 8 scales 4x too
+        assert_eq!(ToleranceLevel::Small.max_distance(256), 8 * 4);
+    }
+
+    #[test]
+    fn max_distance_falls_back_to_the_smallest_table_entry_below_the_smallest_size() {
+        // 4 bits has no tabulated entry <= it, so the smallest (8-bit) entry is used as-is
+        assert_eq!(ToleranceLevel::Small.max_distance(4), 1);
+    }
+
+    #[test]
+    fn stricter_levels_stay_tighter_than_looser_ones_at_the_same_size() {
+        assert!(ToleranceLevel::VerySmall.max_distance(64) < ToleranceLevel::Small.max_distance(64));
+        assert!(ToleranceLevel::Small.max_distance(64) < ToleranceLevel::Medium.max_distance(64));
+        assert!(ToleranceLevel::Medium.max_distance(64) < ToleranceLevel::High.max_distance(64));
+        assert!(ToleranceLevel::High.max_distance(64) < ToleranceLevel::VeryHigh.max_distance(64));
+    }
+
+    #[test]
+    fn parse_accepts_all_known_levels() {
+        assert_eq!(ToleranceLevel::parse("verysmall").unwrap(), ToleranceLevel::VerySmall);
+        assert_eq!(ToleranceLevel::parse("veryhigh").unwrap(), ToleranceLevel::VeryHigh);
+    }
+
+    #[test]
+    fn parse_rejects_an_unknown_level() {
+        assert!(ToleranceLevel::parse("bogus").is_err());
+    }
+}
@@ -1,25 +1,49 @@
 extern crate clap;
 extern crate opencv;
+extern crate rayon;
 mod image_similarity;
 
 use clap::{Arg, App, SubCommand};
-use image_similarity::{similarity, similarity_directory, similarity_file_directory};
+use image_similarity::{similarity, similarity_directory, similarity_directory_indexed, similarity_file_directory, similarity_file_directory_indexed, HashAlgo};
+use image_similarity::cache::HashCache;
+use image_similarity::decode::DEFAULT_EXTENSIONS;
+use image_similarity::threshold::ToleranceLevel;
 use opencv::core::Mat;
 use opencv::imgcodecs::imread;
 
+/// Default location of the on-disk hash cache, relative to the current directory
+const DEFAULT_CACHE_PATH: &str = ".image-similarity-cache";
+
+/// `directory`/`match` always hash with a 16x16 grid (see the hardcoded `64, 16`
+/// passed to `compute_hash` in `image_similarity`), so `--level` is resolved
+/// against that bit size
+const HASH_BIT_SIZE: u32 = 16 * 16;
+
 /// Compute image similarity with image a and image b
-fn compare_pair(image_a: &str, image_b: &str) {
+fn compare_pair(image_a: &str, image_b: &str, algo: HashAlgo) {
     let img_a: Mat = imread(image_a, 0).expect("Image A is not a valid file");
     let img_b: Mat = imread(image_b, 0).expect("Image B is not a valid file");
-    match similarity(&img_a, &img_b, 64, 16) {
+    match similarity(&img_a, &img_b, algo, 64, 16) {
         Ok(sim) => println!("{}", sim),
         Err(e) => println!("[ERROR] {}", e),
     }
 }
 
 /// Compute image similarity of all image pairs with allowed extensions in given directory
-fn compare_directory(directory: &str, allowed_ext: &Vec<&str>) {
-    match similarity_directory(directory, allowed_ext) {
+///
+/// When `max_distance` is given, this queries a BK-tree index instead of
+/// enumerating every pair, since the index can only prune its search given a
+/// concrete distance bound to search within
+fn compare_directory(directory: &str, allowed_ext: &Vec<&str>, algo: HashAlgo, cache_path: Option<&str>, max_distance: Option<u32>) {
+    let mut cache = cache_path.map(HashCache::load);
+    let (similarities, warnings) = match max_distance {
+        Some(max_distance) => match similarity_directory_indexed(directory, allowed_ext, algo, cache.as_mut(), max_distance) {
+            Ok((similarity, warnings)) => (Some(similarity), warnings),
+            Err(e) => { println!("[ERROR] {}", e); (None, Vec::new()) },
+        },
+        None => similarity_directory(directory, allowed_ext, algo, cache.as_mut(), max_distance),
+    };
+    match similarities {
         Some(similarity) => {
             similarity.iter().for_each(|result| {
                 println!("{} \"{}\" \"{}\"", result.0, result.1, result.2);
@@ -27,22 +51,94 @@ fn compare_directory(directory: &str, allowed_ext: &Vec<&str>) {
         },
         None => ()
     };
+    print_warnings(&warnings);
+    save_cache(cache.as_ref(), cache_path);
 }
 
 /// Compute similarities of given image with all images that ends in allowed extensions in given directory
-fn compare_match(image: &str, directory: &str, allowed_ext: &Vec<&str>) {
+///
+/// When `max_distance` is given, this queries a BK-tree index instead of a
+/// linear scan, same as `compare_directory`
+fn compare_match(image: &str, directory: &str, allowed_ext: &Vec<&str>, algo: HashAlgo, cache_path: Option<&str>, max_distance: Option<u32>) {
     let image = imread(image, 0).unwrap();
-    match similarity_file_directory(&image, directory, allowed_ext) {
-        Ok(similarity) => match similarity {
-            None => (),
-            Some(similarity) => {
-                similarity.iter().for_each(|result| {
-                    println!("{} \"{}\"", result.0, result.1);
-                });
+    let mut cache = cache_path.map(HashCache::load);
+    let result = match max_distance {
+        Some(max_distance) => similarity_file_directory_indexed(&image, directory, allowed_ext, algo, cache.as_mut(), max_distance)
+            .map(|(similarity, warnings)| (Some(similarity), warnings)),
+        None => similarity_file_directory(&image, directory, allowed_ext, algo, cache.as_mut(), max_distance),
+    };
+    match result {
+        Ok((similarity, warnings)) => {
+            match similarity {
+                None => (),
+                Some(similarity) => {
+                    similarity.iter().for_each(|result| {
+                        println!("{} \"{}\"", result.0, result.1);
+                    });
+                }
             }
+            print_warnings(&warnings);
         },
         Err(e) => println!("[ERROR] {}", e),
     }
+    save_cache(cache.as_ref(), cache_path);
+}
+
+/// Print one line to stderr per skipped file, so scanning a photo library
+/// surfaces which files couldn't be read instead of silently dropping them
+fn print_warnings(warnings: &[String]) {
+    for warning in warnings {
+        eprintln!("[WARN] {}", warning);
+    }
+}
+
+/// Persist the hash cache back to disk, if it was loaded in the first place
+fn save_cache(cache: Option<&HashCache>, cache_path: Option<&str>) {
+    if let (Some(cache), Some(path)) = (cache, cache_path) {
+        if let Err(e) = cache.save(path) {
+            println!("[ERROR] {}", e);
+        }
+    }
+}
+
+/// Parse the `--algo` flag, defaulting to pHash when not given
+fn get_algo(matches: &clap::ArgMatches) -> HashAlgo {
+    match matches.value_of("algo") {
+        Some(algo) => HashAlgo::parse(algo).expect("Invalid --algo value"),
+        None => HashAlgo::Dct,
+    }
+}
+
+/// Parse the `--cache-path`/`--no-cache` flags into the cache path to use, if any
+fn get_cache_path<'a>(matches: &'a clap::ArgMatches) -> Option<&'a str> {
+    if matches.is_present("no-cache") {
+        None
+    } else {
+        Some(matches.value_of("cache-path").unwrap_or(DEFAULT_CACHE_PATH))
+    }
+}
+
+/// Parse the `--max-distance`/`--level` flags into a raw hamming-bit threshold, if
+/// either was given; the two flags conflict, so at most one of them is present
+fn get_max_distance(matches: &clap::ArgMatches) -> Option<u32> {
+    if let Some(raw) = matches.value_of("max-distance") {
+        return Some(raw.parse().expect("--max-distance must be a non-negative integer"));
+    }
+    matches.value_of("level").map(|level| {
+        ToleranceLevel::parse(level).expect("Invalid --level value").max_distance(HASH_BIT_SIZE)
+    })
+}
+
+/// Configure the size of the global rayon pool from the `--threads` flag; 0 (the
+/// default) means let rayon use all cores
+fn configure_threads(matches: &clap::ArgMatches) {
+    let threads: usize = matches.value_of("threads")
+        .map(|threads| threads.parse().expect("--threads must be a non-negative integer"))
+        .unwrap_or(0);
+    if threads > 0 {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()
+            .expect("failed to configure the rayon thread pool");
+    }
 }
 
 fn main() {
@@ -64,7 +160,12 @@ fn main() {
                 .long("imageb")
                 .help("Image B")
                 .takes_value(true)
-                .required(true)))
+                .required(true))
+            .arg(Arg::with_name("algo")
+                .long("algo")
+                .help("Hash algorithm to use: ahash, dhash or phash, defaults to phash")
+                .takes_value(true)
+                .required(false)))
         .subcommand(SubCommand::with_name("directory")
             .about("Compute image similarity of all image pairs with allowed extensions in given directory")
             .version("0.1.0")
@@ -77,9 +178,40 @@ fn main() {
             .arg(Arg::with_name("extension")
                 .short("e")
                 .long("ext")
-                .help("Allowed extensions, defaults are \"png,jpg,jpeg\"")
+                .help("Allowed extensions, defaults to png/jpg/jpeg plus HEIC/HEIF and common RAW formats")
                 .takes_value(true)
-                .required(false)))
+                .required(false))
+            .arg(Arg::with_name("algo")
+                .long("algo")
+                .help("Hash algorithm to use: ahash, dhash or phash, defaults to phash")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("cache-path")
+                .long("cache-path")
+                .help("Path to the on-disk hash cache, defaults to \".image-similarity-cache\"")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("no-cache")
+                .long("no-cache")
+                .help("Disable the on-disk hash cache")
+                .takes_value(false)
+                .required(false))
+            .arg(Arg::with_name("threads")
+                .long("threads")
+                .help("Number of threads to use, 0 (default) uses all cores")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("max-distance")
+                .long("max-distance")
+                .help("Drop pairs more than this many hamming bits apart")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("level")
+                .long("level")
+                .help("Drop pairs below this named closeness level: verysmall, small, medium, high or veryhigh")
+                .takes_value(true)
+                .required(false)
+                .conflicts_with("max-distance")))
         .subcommand(SubCommand::with_name("match")
             .about("Compute similarities of given image with all images that ends in allowed extensions in given directory")
             .version("0.1.0")
@@ -98,38 +230,71 @@ fn main() {
             .arg(Arg::with_name("extension")
                 .short("e")
                 .long("ext")
-                .help("Allowed extensions, defaults are \"png,jpg,jpeg\"")
+                .help("Allowed extensions, defaults to png/jpg/jpeg plus HEIC/HEIF and common RAW formats")
                 .takes_value(true)
-                .required(false)))
+                .required(false))
+            .arg(Arg::with_name("algo")
+                .long("algo")
+                .help("Hash algorithm to use: ahash, dhash or phash, defaults to phash")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("cache-path")
+                .long("cache-path")
+                .help("Path to the on-disk hash cache, defaults to \".image-similarity-cache\"")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("no-cache")
+                .long("no-cache")
+                .help("Disable the on-disk hash cache")
+                .takes_value(false)
+                .required(false))
+            .arg(Arg::with_name("threads")
+                .long("threads")
+                .help("Number of threads to use, 0 (default) uses all cores")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("max-distance")
+                .long("max-distance")
+                .help("Drop results more than this many hamming bits apart")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("level")
+                .long("level")
+                .help("Drop results below this named closeness level: verysmall, small, medium, high or veryhigh")
+                .takes_value(true)
+                .required(false)
+                .conflicts_with("max-distance")))
         .get_matches();
-    
+
     if let Some(matches) = matches.subcommand_matches("pair") {
         let image_a = matches.value_of("imagea").unwrap();
         let image_b = matches.value_of("imageb").unwrap();
-        compare_pair(image_a, image_b);
+        compare_pair(image_a, image_b, get_algo(matches));
     } else if let Some(matches) = matches.subcommand_matches("directory") {
+        configure_threads(matches);
         let directory = matches.value_of("directory").unwrap();
         let exts = match matches.value_of("extension") {
             Some(extension) => get_extension(extension),
-            None => vec!["png", "jpg", "jpeg"],
+            None => DEFAULT_EXTENSIONS.to_vec(),
         };
-        compare_directory(directory, &exts);
+        compare_directory(directory, &exts, get_algo(matches), get_cache_path(matches), get_max_distance(matches));
     } else if let Some(matches) = matches.subcommand_matches("match") {
+        configure_threads(matches);
         let image = matches.value_of("image").unwrap();
-        
+
         let directory = matches.value_of("directory").unwrap();
         let exts = match matches.value_of("extension") {
             Some(extension) => get_extension(extension),
-            None => vec!["png", "jpg", "jpeg"],
+            None => DEFAULT_EXTENSIONS.to_vec(),
         };
-        compare_match(image, directory, &exts);
+        compare_match(image, directory, &exts, get_algo(matches), get_cache_path(matches), get_max_distance(matches));
     }
 }
 
 fn get_extension<'a>(extension_str: &'a str) -> Vec<&'a str> {
     let exts: Vec<&str> = extension_str.split(',').collect();
     match exts.len() {
-        0 => vec!["png", "jpg", "jpeg"],
+        0 => DEFAULT_EXTENSIONS.to_vec(),
         _ => exts,
     }
 }